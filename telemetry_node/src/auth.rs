@@ -0,0 +1,293 @@
+//! JWT-based request authentication with Redis-backed session revocation.
+//!
+//! Every bearer token is a short-lived HS256 JWT carrying a unique `jti`
+//! (session id). Beyond the usual signature/`exp` check, each request looks
+//! the `jti` up in Redis so a compromised or logged-out device can be
+//! force-logged-out immediately instead of waiting out the token's natural
+//! expiry.
+//!
+//! Two revocation strategies are supported via `JTI_REVOCATION_MODE`:
+//! - `deny` (default): sessions are valid until explicitly revoked, tracked
+//!   as `revoked_jti:{jti}` keys whose TTL is set to the session's remaining
+//!   lifetime at revoke time, so the deny list self-cleans instead of
+//!   growing forever.
+//! - `allow`: sessions must be explicitly issued and are tracked as
+//!   `session:{jti}` keys with a TTL matching the token's remaining
+//!   lifetime; letting the key expire naturally logs the session out.
+
+use crate::{AppState, NodeError};
+use axum::{extract::State, http::HeaderMap, http::StatusCode, Json};
+use deadpool_redis::{redis::AsyncCommands, Connection};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+const REVOKED_JTI_PREFIX: &str = "revoked_jti:";
+const SESSION_KEY_PREFIX: &str = "session:";
+/// Bookkeeping key written at issue time for every session (regardless of
+/// revocation mode) purely so `revoke` can recover how much lifetime a
+/// session had left, to size the deny-list entry's TTL.
+const SESSION_EXP_PREFIX: &str = "session_exp:";
+/// Used when a session's remaining lifetime can't be determined (e.g. a
+/// token issued before this bookkeeping key existed, or one that's already
+/// past its `exp`) — long enough to be safe, short enough not to linger.
+const FALLBACK_REVOCATION_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Claims that carry a session identifier, so revocation can be keyed off of
+/// something narrower than the whole token.
+pub trait Claims {
+    fn jti(&self) -> Uuid;
+    fn exp(&self) -> usize;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtClaims {
+    pub sub: Option<String>,
+    pub exp: usize,
+    pub jti: Uuid,
+}
+
+impl Claims for JwtClaims {
+    fn jti(&self) -> Uuid {
+        self.jti
+    }
+
+    fn exp(&self) -> usize {
+        self.exp
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevocationMode {
+    DenyList,
+    AllowList,
+}
+
+impl RevocationMode {
+    pub fn from_env() -> Self {
+        match std::env::var("JTI_REVOCATION_MODE").as_deref() {
+            Ok("allow") => Self::AllowList,
+            _ => Self::DenyList,
+        }
+    }
+}
+
+/// How long a freshly-written `revoked_jti:{jti}` deny-list entry should
+/// live, given the `TTL` Redis reports on the matching `session_exp` key.
+/// Pulled out of `revoke_session_handler` so the edge cases (no bookkeeping
+/// key, key already expired) are covered without a Redis connection.
+fn deny_list_ttl(remaining_ttl: i64) -> u64 {
+    if remaining_ttl > 0 {
+        remaining_ttl as u64
+    } else {
+        FALLBACK_REVOCATION_TTL_SECS
+    }
+}
+
+fn decode_jwt(token: &str, secret: &str) -> Result<JwtClaims, NodeError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_exp = true;
+
+    decode::<JwtClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &validation,
+    )
+    .map(|data| data.claims)
+    .map_err(|_| NodeError::Unauthorized)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<&str, NodeError> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or(NodeError::Unauthorized)
+}
+
+/// Gates the session-management endpoints: issuing or revoking a session is
+/// an operator action, not something any caller holding a regular device
+/// JWT should be able to do, so it's checked against a separate shared
+/// secret rather than `authorize`'s per-session JWT flow. Unlike the JWTs,
+/// this secret is a standing credential rather than something signed, so
+/// it's compared in constant time to avoid a timing side-channel on `==`.
+pub fn authorize_operator(headers: &HeaderMap, operator_token: &str) -> Result<(), NodeError> {
+    let token = bearer_token(headers)?;
+    if token.as_bytes().ct_eq(operator_token.as_bytes()).into() {
+        Ok(())
+    } else {
+        Err(NodeError::Unauthorized)
+    }
+}
+
+/// Verifies the bearer JWT's signature and expiry, then consults Redis to
+/// make sure its session hasn't been revoked (or, in allow-list mode, is
+/// still active).
+pub async fn authorize(
+    headers: &HeaderMap,
+    jwt_secret: &str,
+    revocation_mode: RevocationMode,
+    conn: &mut Connection,
+) -> Result<JwtClaims, NodeError> {
+    let token = bearer_token(headers)?;
+    let claims = decode_jwt(token, jwt_secret)?;
+    let jti = claims.jti().to_string();
+
+    match revocation_mode {
+        RevocationMode::DenyList => {
+            let revoked: bool = conn
+                .exists(format!("{REVOKED_JTI_PREFIX}{jti}"))
+                .await
+                .map_err(|e| NodeError::RedisError(format!("revocation lookup failed: {e}")))?;
+            if revoked {
+                return Err(NodeError::Unauthorized);
+            }
+        }
+        RevocationMode::AllowList => {
+            let active: bool = conn
+                .exists(format!("{SESSION_KEY_PREFIX}{jti}"))
+                .await
+                .map_err(|e| NodeError::RedisError(format!("session lookup failed: {e}")))?;
+            if !active {
+                return Err(NodeError::Unauthorized);
+            }
+        }
+    }
+
+    Ok(claims)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueSessionRequest {
+    pub sub: String,
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssueSessionResponse {
+    pub token: String,
+    pub jti: Uuid,
+}
+
+/// Mints a fresh JWT for `sub`. Always writes the `session_exp:{jti}`
+/// bookkeeping key (TTL = `ttl_secs`) so a later `revoke` can recover how
+/// long the session had left; in allow-list mode this also writes the
+/// `session:{jti}` key that makes the token valid in the first place, while
+/// in deny-list mode the token is valid as soon as it's issued.
+pub async fn issue_session_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<IssueSessionRequest>,
+) -> Result<Json<IssueSessionResponse>, NodeError> {
+    authorize_operator(&headers, &state.operator_token)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| NodeError::Internal(format!("system clock error: {e}")))?
+        .as_secs();
+
+    let jti = Uuid::new_v4();
+    let claims = JwtClaims {
+        sub: Some(req.sub),
+        exp: (now + req.ttl_secs) as usize,
+        jti,
+    };
+
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    )
+    .map_err(|e| NodeError::Internal(format!("token signing failed: {e}")))?;
+
+    let mut conn = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| NodeError::RedisError(format!("pool get failed: {e}")))?;
+
+    let exp_key = format!("{SESSION_EXP_PREFIX}{jti}");
+    let _: () = conn
+        .set_ex(exp_key, "1", req.ttl_secs)
+        .await
+        .map_err(|e| NodeError::RedisError(format!("session bookkeeping write failed: {e}")))?;
+
+    if state.revocation_mode == RevocationMode::AllowList {
+        let key = format!("{SESSION_KEY_PREFIX}{jti}");
+        let _: () = conn
+            .set_ex(key, "1", req.ttl_secs)
+            .await
+            .map_err(|e| NodeError::RedisError(format!("session write failed: {e}")))?;
+    }
+
+    Ok(Json(IssueSessionResponse { token, jti }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeSessionRequest {
+    pub jti: Uuid,
+}
+
+/// Force-logs-out a single session immediately, regardless of how long it
+/// has left before its natural `exp`.
+pub async fn revoke_session_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<RevokeSessionRequest>,
+) -> Result<StatusCode, NodeError> {
+    authorize_operator(&headers, &state.operator_token)?;
+
+    let mut conn = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| NodeError::RedisError(format!("pool get failed: {e}")))?;
+
+    match state.revocation_mode {
+        RevocationMode::DenyList => {
+            let exp_key = format!("{SESSION_EXP_PREFIX}{}", req.jti);
+            let remaining_ttl: i64 = conn
+                .ttl(&exp_key)
+                .await
+                .map_err(|e| NodeError::RedisError(format!("ttl lookup failed: {e}")))?;
+            let revoke_ttl = deny_list_ttl(remaining_ttl);
+
+            let key = format!("{REVOKED_JTI_PREFIX}{}", req.jti);
+            let _: () = conn
+                .set_ex(key, "1", revoke_ttl)
+                .await
+                .map_err(|e| NodeError::RedisError(format!("revoke failed: {e}")))?;
+        }
+        RevocationMode::AllowList => {
+            let key = format!("{SESSION_KEY_PREFIX}{}", req.jti);
+            let _: usize = conn
+                .del(key)
+                .await
+                .map_err(|e| NodeError::RedisError(format!("revoke failed: {e}")))?;
+        }
+    }
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_list_ttl_uses_remaining_session_lifetime() {
+        assert_eq!(deny_list_ttl(42), 42);
+    }
+
+    #[test]
+    fn deny_list_ttl_falls_back_when_expired_or_unknown() {
+        assert_eq!(deny_list_ttl(0), FALLBACK_REVOCATION_TTL_SECS);
+        assert_eq!(deny_list_ttl(-2), FALLBACK_REVOCATION_TTL_SECS);
+    }
+}
@@ -0,0 +1,147 @@
+//! Redis GEO indexing of live positions and a proximity query endpoint.
+//!
+//! Every telemetry update also lands in the `duty_positions` geospatial
+//! index (`GEOADD`), so current positions stay queryable independent of the
+//! publish/subscribe feed — e.g. "who is near this incident" dispatch
+//! lookups via `GEORADIUS`.
+
+use crate::{auth, AppState, NodeError};
+use axum::{
+    extract::{RawQuery, State},
+    http::HeaderMap,
+    Json,
+};
+use deadpool_redis::redis::{
+    geo::{RadiusOptions, RadiusSearchResult, Unit},
+    AsyncCommands,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub const DUTY_POSITIONS_KEY: &str = "duty_positions";
+
+/// Shared by `handle_telemetry` and `handle_nearby` so both endpoints reject
+/// the same malformed coordinates the same way.
+pub fn validate_coords(lat: f64, lon: f64) -> Result<(), NodeError> {
+    if !lat.is_finite() || !lon.is_finite() {
+        return Err(NodeError::InvalidPayload(
+            "Coordinates must be finite numbers".to_string(),
+        ));
+    }
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(NodeError::InvalidPayload(
+            "lat/lon out of range".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NearbyQuery {
+    pub lat: f64,
+    pub lon: f64,
+    pub radius_m: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NearbyMatch {
+    pub user_id: String,
+    pub distance_m: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NearbyResponse {
+    pub matches: Vec<NearbyMatch>,
+}
+
+pub async fn handle_nearby(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Json<NearbyResponse>, NodeError> {
+    let mut con = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| NodeError::RedisError(format!("pool get failed: {e}")))?;
+
+    // Zero Trust: authorize before doing any other work, including payload
+    // validation, so an unauthenticated caller can't use this endpoint as a
+    // validation oracle. Live position read-out
+    // is just as sensitive as ingestion, so it's gated the same way: a
+    // valid, unrevoked session JWT is required.
+    //
+    // This is also why `NearbyQuery` isn't taken as an `axum::extract::Query`
+    // handler parameter: that extractor runs (and 400s on a malformed
+    // `lat`/`lon`/`radius_m`) before the handler body ever executes, which
+    // would let an unauthenticated caller reach the deserialize-failure path
+    // without ever going through `authorize`. Taking the raw query string
+    // instead and parsing it manually after `authorize` keeps every
+    // validation failure behind authorization (unlike `handle_telemetry`,
+    // whose JSON body is extracted before its handler body runs).
+    auth::authorize(&headers, &state.jwt_secret, state.revocation_mode, &mut con).await?;
+
+    let query: NearbyQuery = serde_urlencoded::from_str(raw_query.as_deref().unwrap_or(""))
+        .map_err(|e| NodeError::InvalidPayload(format!("invalid query string: {e}")))?;
+
+    validate_coords(query.lat, query.lon)?;
+    if !query.radius_m.is_finite() || query.radius_m <= 0.0 {
+        return Err(NodeError::InvalidPayload(
+            "radius_m must be a positive, finite number".to_string(),
+        ));
+    }
+
+    // `geo_radius` issues the deprecated `GEORADIUS` rather than
+    // `GEOSEARCH ... BYRADIUS` — the deadpool-redis version this node is
+    // pinned to doesn't expose `geo_search` yet. Functionally equivalent for
+    // our fixed-center, fixed-radius query shape; the error message below is
+    // worded to match the command actually sent instead of the aspirational one.
+    let options = RadiusOptions::default().with_dist();
+    let results: Vec<RadiusSearchResult> = con
+        .geo_radius(
+            DUTY_POSITIONS_KEY,
+            query.lon,
+            query.lat,
+            query.radius_m,
+            Unit::Meters,
+            options,
+        )
+        .await
+        .map_err(|e| NodeError::RedisError(format!("GEORADIUS failed: {e}")))?;
+
+    let matches = results
+        .into_iter()
+        .map(|r| NearbyMatch {
+            user_id: r.name,
+            distance_m: r.dist.unwrap_or_default(),
+        })
+        .collect();
+
+    Ok(Json(NearbyResponse { matches }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_coords_accepts_valid_points() {
+        assert!(validate_coords(51.5074, -0.1278).is_ok());
+        assert!(validate_coords(90.0, 180.0).is_ok());
+        assert!(validate_coords(-90.0, -180.0).is_ok());
+    }
+
+    #[test]
+    fn validate_coords_rejects_out_of_range() {
+        assert!(validate_coords(90.1, 0.0).is_err());
+        assert!(validate_coords(0.0, 180.1).is_err());
+        assert!(validate_coords(-90.1, 0.0).is_err());
+        assert!(validate_coords(0.0, -180.1).is_err());
+    }
+
+    #[test]
+    fn validate_coords_rejects_non_finite() {
+        assert!(validate_coords(f64::NAN, 0.0).is_err());
+        assert!(validate_coords(0.0, f64::INFINITY).is_err());
+    }
+}
@@ -2,16 +2,29 @@ use axum::{
     extract::State,
     http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Json, Router,
 };
 use deadpool_redis::{redis::AsyncCommands, Config as RedisPoolConfig, Pool, Runtime};
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use tracing::{error, info, warn};
 
-#[derive(Deserialize, Serialize, Debug)]
+mod auth;
+mod geo;
+mod metrics;
+mod stream;
+mod tls;
+
+use auth::RevocationMode;
+use stream::{Broadcaster, UnitHub};
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
 struct TelemetryPayload {
     user_id: String,
     lat: f64,
@@ -20,20 +33,25 @@ struct TelemetryPayload {
     unit_label: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct WsMessage {
     event: String,
     data: TelemetryPayload,
 }
 
 #[derive(Clone)]
-struct AppState {
-    redis_pool: Pool,
-    node_token: Option<String>,
+pub(crate) struct AppState {
+    pub(crate) redis_pool: Pool,
+    pub(crate) jwt_secret: String,
+    pub(crate) operator_token: String,
+    pub(crate) revocation_mode: RevocationMode,
+    pub(crate) broadcaster: Broadcaster,
+    pub(crate) unit_hub: UnitHub,
+    pub(crate) metrics_handle: PrometheusHandle,
 }
 
 #[derive(Debug, Error)]
-enum NodeError {
+pub(crate) enum NodeError {
     #[error("Redis error: {0}")]
     RedisError(String),
     #[error("Unauthorized")]
@@ -70,45 +88,35 @@ impl IntoResponse for NodeError {
     }
 }
 
-fn authorize(headers: &HeaderMap, expected_token: Option<&str>) -> Result<(), NodeError> {
-    let Some(expected) = expected_token else {
-        return Ok(());
-    };
-
-    let provided = headers
-        .get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
-        .map(str::trim)
-        .or_else(|| {
-            headers
-                .get("x-node-token")
-                .and_then(|v| v.to_str().ok())
-                .map(str::trim)
-        });
-
-    match provided {
-        Some(token) if token == expected => Ok(()),
-        _ => Err(NodeError::Unauthorized),
-    }
-}
-
 async fn handle_telemetry(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Json(payload): Json<TelemetryPayload>,
 ) -> Result<impl IntoResponse, NodeError> {
-    authorize(&headers, state.node_token.as_deref())?;
+    // Zero Trust: authorize before the coordinate checks, so an
+    // unauthenticated caller can't use them as a validation oracle. The JSON
+    // body itself is deserialized by the `Json` extractor before this handler
+    // runs, so a malformed body is rejected without going through `authorize`.
+    let mut con = state.redis_pool.get().await.map_err(|e| {
+        ::metrics::counter!("telemetry_requests_total", "result" => "redis_error").increment(1);
+        NodeError::RedisError(format!("pool get failed: {e}"))
+    })?;
 
-    if !payload.lat.is_finite() || !payload.lon.is_finite() {
-        return Err(NodeError::InvalidPayload(
-            "Coordinates must be finite numbers".to_string(),
-        ));
-    }
-    if !(-90.0..=90.0).contains(&payload.lat) || !(-180.0..=180.0).contains(&payload.lon) {
-        return Err(NodeError::InvalidPayload(
-            "lat/lon out of range".to_string(),
-        ));
+    auth::authorize(&headers, &state.jwt_secret, state.revocation_mode, &mut con)
+        .await
+        .map_err(|e| {
+            let result = if matches!(e, NodeError::Unauthorized) {
+                "unauthorized"
+            } else {
+                "redis_error"
+            };
+            ::metrics::counter!("telemetry_requests_total", "result" => result).increment(1);
+            e
+        })?;
+
+    if let Err(e) = geo::validate_coords(payload.lat, payload.lon) {
+        ::metrics::counter!("telemetry_requests_total", "result" => "invalid_payload").increment(1);
+        return Err(e);
     }
 
     let ws_msg = WsMessage {
@@ -116,18 +124,49 @@ async fn handle_telemetry(
         data: payload,
     };
 
-    let msg_str = serde_json::to_string(&ws_msg)
-        .map_err(|e| NodeError::InvalidPayload(format!("serialization failed: {e}")))?;
+    let msg_str = serde_json::to_string(&ws_msg).map_err(|e| {
+        ::metrics::counter!("telemetry_requests_total", "result" => "invalid_payload").increment(1);
+        NodeError::InvalidPayload(format!("serialization failed: {e}"))
+    })?;
 
-    let mut con = state
-        .redis_pool
-        .get()
-        .await
-        .map_err(|e| NodeError::RedisError(format!("pool get failed: {e}")))?;
+    let publish_started = Instant::now();
+    let publish_result: Result<usize, _> = con.publish("map_updates", msg_str.clone()).await;
+    ::metrics::histogram!("redis_publish_latency_seconds")
+        .record(publish_started.elapsed().as_secs_f64());
+    publish_result.map_err(|e| {
+        ::metrics::counter!("telemetry_requests_total", "result" => "redis_error").increment(1);
+        NodeError::RedisError(format!("publish failed: {e}"))
+    })?;
 
-    let publish_result: Result<usize, _> = con.publish("map_updates", msg_str).await;
-    publish_result.map_err(|e| NodeError::RedisError(format!("publish failed: {e}")))?;
+    // Also publish to a per-unit channel so clients with a narrow `?unit=`
+    // filter can subscribe to just that feed instead of the full firehose.
+    if let Some(unit) = ws_msg.data.unit_label.as_deref() {
+        let channel = format!("map_updates:{unit}");
+        let unit_publish: Result<usize, _> = con.publish(channel, msg_str).await;
+        if let Err(e) = unit_publish {
+            warn!(error = %e, unit, "failed to publish per-unit channel");
+        }
+    }
+
+    // Keep the geospatial index current so `/api/duty/nearby` always reflects
+    // the latest known position, independent of who's listening on the feed.
+    let geoadd_result: Result<i64, _> = con
+        .geo_add(
+            geo::DUTY_POSITIONS_KEY,
+            (ws_msg.data.lon, ws_msg.data.lat, ws_msg.data.user_id.as_str()),
+        )
+        .await;
+    if let Err(e) = geoadd_result {
+        warn!(error = %e, "failed to GEOADD duty_positions");
+    }
 
+    // Note: we don't also push `ws_msg` into `state.broadcaster` here — the
+    // background `run_redis_subscriber` task is already subscribed to
+    // `map_updates` and will receive this same publish, so it's the single
+    // source of truth for the in-process fan-out. Feeding the broadcaster
+    // directly here as well would double-deliver every firehose message.
+
+    ::metrics::counter!("telemetry_requests_total", "result" => "ok").increment(1);
     Ok((StatusCode::OK, "OK"))
 }
 
@@ -140,7 +179,16 @@ async fn main() -> Result<(), NodeError> {
         .init();
 
     let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
-    let node_token = std::env::var("NODE_TOKEN").ok().filter(|v| !v.trim().is_empty());
+    let jwt_secret =
+        std::env::var("NODE_JWT_SECRET").map_err(|_| {
+            NodeError::Internal("NODE_JWT_SECRET is required for JWT validation".to_string())
+        })?;
+    let operator_token = std::env::var("OPERATOR_TOKEN").map_err(|_| {
+        NodeError::Internal(
+            "OPERATOR_TOKEN is required to gate /api/duty/session/* endpoints".to_string(),
+        )
+    })?;
+    let revocation_mode = RevocationMode::from_env();
 
     let mut cfg = RedisPoolConfig::from_url(redis_url);
     cfg.pool = Some(deadpool_redis::PoolConfig::new(32));
@@ -150,22 +198,73 @@ async fn main() -> Result<(), NodeError> {
         .create_pool(Some(Runtime::Tokio1))
         .map_err(|e| NodeError::Internal(format!("redis pool init failed: {e}")))?;
 
+    let broadcaster = Broadcaster::new();
+
+    let subscriber_handle = tokio::spawn(stream::run_redis_subscriber(
+        redis_url.clone(),
+        "map_updates".to_string(),
+        broadcaster.clone(),
+    ));
+
+    let unit_hub = UnitHub::new(redis_url.clone());
+    let metrics_handle = metrics::install_recorder();
+
     let state = Arc::new(AppState {
         redis_pool: pool,
-        node_token,
+        jwt_secret,
+        operator_token,
+        revocation_mode,
+        broadcaster,
+        unit_hub,
+        metrics_handle,
     });
 
     let app = Router::new()
         .route("/api/duty/telemetry/fast", post(handle_telemetry))
+        .route("/api/duty/stream/ws", get(stream::ws_stream_handler))
+        .route("/api/duty/stream/sse", get(stream::sse_stream_handler))
+        .route("/api/duty/session/issue", post(auth::issue_session_handler))
+        .route(
+            "/api/duty/session/revoke",
+            post(auth::revoke_session_handler),
+        )
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/api/duty/nearby", get(geo::handle_nearby))
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .map_err(|e| NodeError::Internal(format!("bind failed: {e}")))?;
+    let addr: std::net::SocketAddr = "0.0.0.0:3000".parse().expect("hardcoded addr is valid");
+    let serve_result = match tls::from_env() {
+        Some(tls_paths) => {
+            let tls_config = tls::load_rustls_config(&tls_paths).await?;
+            info!("Rust Telemetry Node started on {addr} (TLS, HTTP/2)");
+
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                shutdown_handle.graceful_shutdown(Some(Duration::from_secs(10)));
+            });
 
-    info!("Rust Telemetry Node started on 0.0.0.0:3000");
+            axum_server::bind_rustls(addr, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| NodeError::Internal(format!("bind failed: {e}")))?;
+
+            info!("Rust Telemetry Node started on {addr} (plaintext)");
+
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+        }
+    };
+
+    subscriber_handle.abort();
 
-    let serve_result = axum::serve(listener, app).await;
     if let Err(e) = serve_result {
         error!(error = %e, "server error");
         return Err(NodeError::Internal(format!("server error: {e}")));
@@ -174,3 +273,9 @@ async fn main() -> Result<(), NodeError> {
     warn!("server stopped gracefully");
     Ok(())
 }
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install Ctrl+C handler");
+}
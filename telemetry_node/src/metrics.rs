@@ -0,0 +1,41 @@
+//! Prometheus metrics for telemetry throughput and error rates.
+//!
+//! The recorder is installed once at startup; `handle_telemetry` and the
+//! streaming layer record into it via the global `metrics` macros, and
+//! `GET /metrics` renders whatever has accumulated so operators get
+//! per-outcome request counts and tail-latency visibility without scraping
+//! logs.
+
+use crate::AppState;
+use axum::extract::State;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::sync::Arc;
+
+pub fn install_recorder() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .install_recorder()
+        .expect("failed to install Prometheus recorder")
+}
+
+pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> String {
+    state.metrics_handle.render()
+}
+
+/// Tracks the `telemetry_streaming_clients_connected` gauge: increments on
+/// creation, decrements on drop, so a client's disconnect path (however it
+/// happens — clean close, socket or send error, closed feed) can't forget to
+/// account for it.
+pub struct ConnectedClientGuard;
+
+impl ConnectedClientGuard {
+    pub fn new() -> Self {
+        metrics::gauge!("telemetry_streaming_clients_connected").increment(1.0);
+        Self
+    }
+}
+
+impl Drop for ConnectedClientGuard {
+    fn drop(&mut self) {
+        metrics::gauge!("telemetry_streaming_clients_connected").decrement(1.0);
+    }
+}
@@ -0,0 +1,477 @@
+//! Fan-out of `map_updates` Redis pub/sub traffic to connected browser clients.
+//!
+//! One background task holds the long-lived `SUBSCRIBE` connection to the
+//! firehose `map_updates` channel and feeds everything it reads into a
+//! [`Broadcaster`]. Each WS/SSE client gets its own `broadcast::Receiver`
+//! drained independently, so a slow client can never block the others or the
+//! Redis subscriber itself.
+//!
+//! Both endpoints require a valid, unrevoked session (see [`auth`]) before
+//! handing out a receiver. Clients may further narrow what they receive with
+//! `?unit=<label>` or `?user=<id>` on the stream endpoints. A `user` filter
+//! is just applied against the firehose broadcaster, but a `unit` filter
+//! gets its own dedicated Redis subscriber on `map_updates:{unit}` (see
+//! [`UnitHub`]), so a client watching a single unit never pays the decode
+//! cost of the full feed. A `?user=` filter may only ever target the
+//! caller's own session — otherwise it would let any logged-in client
+//! request a dedicated live tracking feed for someone else.
+
+use crate::{auth, AppState, NodeError, WsMessage};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+};
+use deadpool_redis::redis::AsyncCommands;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
+use tokio::{
+    sync::{broadcast, Mutex},
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{error, warn};
+
+/// How many messages a lagging receiver may fall behind before old ones are
+/// dropped in its favor. Generous enough to absorb a brief client-side stall.
+const BROADCAST_CAPACITY: usize = 1024;
+
+const MAP_UPDATES_CHANNEL: &str = "map_updates";
+
+/// Shared fan-out point for `WsMessage`s. Cheaply `Clone`-able (it's just a
+/// `broadcast::Sender` underneath) so it can live directly on `AppState` or a
+/// [`UnitHub`] entry.
+#[derive(Clone)]
+pub struct Broadcaster {
+    tx: broadcast::Sender<WsMessage>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        let (tx, _rx) = broadcast::channel(BROADCAST_CAPACITY);
+        Self { tx }
+    }
+
+    /// Push a message to every currently-subscribed receiver. A zero-receiver
+    /// send just means nobody is connected right now, which is not an error.
+    pub fn send(&self, msg: WsMessage) {
+        let _ = self.tx.send(msg);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WsMessage> {
+        self.tx.subscribe()
+    }
+}
+
+/// What a connected client wants to see, parsed from its query string.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamQuery {
+    pub unit: Option<String>,
+    pub user: Option<String>,
+}
+
+/// A client's requested filter over the `duty_location_update` stream.
+#[derive(Debug, Clone)]
+pub enum Subscription {
+    All,
+    Unit(String),
+    User(String),
+}
+
+impl Subscription {
+    /// Parses the requested filter, additionally enforcing that a `?user=`
+    /// filter can only ever target the caller's own session — without this,
+    /// an authenticated-but-unprivileged client could hand any `user_id` on
+    /// the query string and get a dedicated live tracking feed for someone
+    /// else entirely, which is a stalking primitive dressed up as a filter.
+    /// `?unit=` is left unrestricted since a unit is a group, not a person.
+    fn from_query(query: &StreamQuery, claims: &auth::JwtClaims) -> Result<Self, NodeError> {
+        match (&query.unit, &query.user) {
+            (Some(unit), _) if !unit.is_empty() => Ok(Subscription::Unit(unit.clone())),
+            (_, Some(user)) if !user.is_empty() => {
+                if claims.sub.as_deref() == Some(user.as_str()) {
+                    Ok(Subscription::User(user.clone()))
+                } else {
+                    Err(NodeError::Unauthorized)
+                }
+            }
+            _ => Ok(Subscription::All),
+        }
+    }
+
+    fn matches(&self, msg: &WsMessage) -> bool {
+        match self {
+            Subscription::All => true,
+            Subscription::Unit(unit) => msg.data.unit_label.as_deref() == Some(unit.as_str()),
+            Subscription::User(user) => msg.data.user_id == *user,
+        }
+    }
+}
+
+/// Holds one on-demand Redis subscriber per unit label that currently has at
+/// least one streaming client watching it, so narrowly-scoped clients skip
+/// the full-firehose decode.
+#[derive(Clone)]
+pub struct UnitHub {
+    redis_url: Arc<str>,
+    channels: Arc<Mutex<HashMap<String, UnitChannel>>>,
+}
+
+struct UnitChannel {
+    broadcaster: Broadcaster,
+    subscribers: usize,
+    task: JoinHandle<()>,
+}
+
+/// Releases this client's interest in a unit channel when dropped, tearing
+/// down the dedicated Redis subscriber once nobody is watching it anymore.
+pub struct UnitGuard {
+    hub: UnitHub,
+    unit: String,
+}
+
+impl Drop for UnitGuard {
+    fn drop(&mut self) {
+        let hub = self.hub.clone();
+        let unit = std::mem::take(&mut self.unit);
+        tokio::spawn(async move { hub.release(&unit).await });
+    }
+}
+
+impl UnitHub {
+    pub fn new(redis_url: String) -> Self {
+        Self {
+            redis_url: Arc::from(redis_url),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Subscribes to the dedicated feed for `unit`, spinning up its Redis
+    /// subscriber task on first interest. The returned `UnitGuard` must be
+    /// held for as long as the caller wants to stay subscribed.
+    pub async fn subscribe(&self, unit: &str) -> (broadcast::Receiver<WsMessage>, UnitGuard) {
+        let mut channels = self.channels.lock().await;
+        let entry = channels.entry(unit.to_string()).or_insert_with(|| {
+            let broadcaster = Broadcaster::new();
+            let channel = format!("{MAP_UPDATES_CHANNEL}:{unit}");
+            let task = tokio::spawn(run_redis_subscriber(
+                self.redis_url.to_string(),
+                channel,
+                broadcaster.clone(),
+            ));
+            UnitChannel {
+                broadcaster,
+                subscribers: 0,
+                task,
+            }
+        });
+        entry.subscribers += 1;
+        let rx = entry.broadcaster.subscribe();
+        drop(channels);
+
+        (
+            rx,
+            UnitGuard {
+                hub: self.clone(),
+                unit: unit.to_string(),
+            },
+        )
+    }
+
+    async fn release(&self, unit: &str) {
+        let mut channels = self.channels.lock().await;
+        let Some(entry) = channels.get_mut(unit) else {
+            return;
+        };
+        entry.subscribers = entry.subscribers.saturating_sub(1);
+        if entry.subscribers == 0 {
+            let entry = channels.remove(unit).expect("just looked up above");
+            entry.task.abort();
+        }
+    }
+}
+
+/// Runs until its task is aborted: holds the Redis `SUBSCRIBE` connection
+/// and republishes every message onto `broadcaster`.
+/// Reconnects with a short backoff if the Redis connection drops.
+pub async fn run_redis_subscriber(redis_url: String, channel: String, broadcaster: Broadcaster) {
+    loop {
+        if let Err(e) = subscribe_once(&redis_url, &channel, &broadcaster).await {
+            error!(error = %e, channel, "subscriber dropped, retrying in 1s");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+async fn subscribe_once(
+    redis_url: &str,
+    channel: &str,
+    broadcaster: &Broadcaster,
+) -> Result<(), NodeError> {
+    let client = deadpool_redis::redis::Client::open(redis_url)
+        .map_err(|e| NodeError::RedisError(format!("client open failed: {e}")))?;
+    let mut pubsub = client
+        .get_async_pubsub()
+        .await
+        .map_err(|e| NodeError::RedisError(format!("pubsub connect failed: {e}")))?;
+    pubsub
+        .subscribe(channel)
+        .await
+        .map_err(|e| NodeError::RedisError(format!("subscribe failed: {e}")))?;
+
+    let mut messages = pubsub.into_on_message();
+    while let Some(msg) = messages.next().await {
+        let payload: String = match msg.get_payload() {
+            Ok(p) => p,
+            Err(e) => {
+                warn!(error = %e, channel, "payload was not a string, skipping");
+                continue;
+            }
+        };
+        match serde_json::from_str::<WsMessage>(&payload) {
+            Ok(ws_msg) => broadcaster.send(ws_msg),
+            Err(e) => warn!(error = %e, channel, "failed to deserialize WsMessage"),
+        }
+    }
+
+    // The stream only ends if the connection was dropped; ask the caller to
+    // reconnect rather than treating it as a clean shutdown.
+    Err(NodeError::RedisError(format!(
+        "{channel} subscription stream ended"
+    )))
+}
+
+/// Either the firehose receiver (filtered in-process) or a dedicated
+/// per-unit receiver (already scoped at the Redis level), plus whatever
+/// cleanup the chosen path needs on disconnect.
+enum Feed {
+    Firehose(broadcast::Receiver<WsMessage>),
+    Unit(broadcast::Receiver<WsMessage>, UnitGuard),
+}
+
+async fn open_feed(state: &AppState, subscription: &Subscription) -> Feed {
+    match subscription {
+        Subscription::Unit(unit) => {
+            let (rx, guard) = state.unit_hub.subscribe(unit).await;
+            Feed::Unit(rx, guard)
+        }
+        Subscription::All | Subscription::User(_) => Feed::Firehose(state.broadcaster.subscribe()),
+    }
+}
+
+impl Feed {
+    fn receiver_mut(&mut self) -> &mut broadcast::Receiver<WsMessage> {
+        match self {
+            Feed::Firehose(rx) => rx,
+            Feed::Unit(rx, _) => rx,
+        }
+    }
+}
+
+pub async fn ws_stream_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, NodeError> {
+    // Same Zero Trust ordering as `handle_telemetry`: authorize before handing
+    // out a receiver, so the live feed can't be reached without a valid,
+    // unrevoked session — otherwise the revocation work is moot for anyone
+    // willing to connect straight to the stream endpoints instead.
+    let mut con = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| NodeError::RedisError(format!("pool get failed: {e}")))?;
+    let claims = auth::authorize(&headers, &state.jwt_secret, state.revocation_mode, &mut con).await?;
+
+    let subscription = Subscription::from_query(&query, &claims)?;
+    Ok(ws.on_upgrade(move |socket| handle_ws_socket(socket, state, subscription)))
+}
+
+async fn handle_ws_socket(mut socket: WebSocket, state: Arc<AppState>, subscription: Subscription) {
+    let _client_guard = crate::metrics::ConnectedClientGuard::new();
+    let mut feed = open_feed(&state, &subscription).await;
+
+    loop {
+        tokio::select! {
+            // Race the broadcast feed against the socket's own read side, so a
+            // client that disconnects on a quiet feed is noticed as soon as
+            // its Close frame or dropped connection comes in, instead of only
+            // on the next outgoing send — which keeps
+            // `telemetry_streaming_clients_connected` accurate in the meantime.
+            incoming = socket.recv() => {
+                match incoming {
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!(error = %e, "ws client socket errored, disconnecting");
+                        break;
+                    }
+                }
+            }
+            msg = feed.receiver_mut().recv() => {
+                match msg {
+                    Ok(msg) => {
+                        if !subscription.matches(&msg) {
+                            continue;
+                        }
+                        let text = match serde_json::to_string(&msg) {
+                            Ok(t) => t,
+                            Err(e) => {
+                                warn!(error = %e, "failed to serialize WsMessage for ws client");
+                                continue;
+                            }
+                        };
+                        if socket.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "ws client lagged behind broadcast, skipping ahead");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+pub async fn sse_stream_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl futures_util::Stream<Item = Result<Event, Infallible>>>, NodeError> {
+    let mut con = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| NodeError::RedisError(format!("pool get failed: {e}")))?;
+    let claims = auth::authorize(&headers, &state.jwt_secret, state.revocation_mode, &mut con).await?;
+
+    let subscription = Subscription::from_query(&query, &claims)?;
+    let feed = open_feed(&state, &subscription).await;
+    let (rx, guard) = match feed {
+        Feed::Firehose(rx) => (rx, None),
+        Feed::Unit(rx, guard) => (rx, Some(guard)),
+    };
+
+    // `guard` is only moved into the closure to keep the dedicated unit
+    // subscriber alive for as long as this stream is — it's torn down once
+    // the closure (and therefore the stream) is dropped at disconnect.
+    let client_guard = crate::metrics::ConnectedClientGuard::new();
+    let stream = BroadcastStream::new(rx).filter_map(move |item| {
+        let _keep_unit_subscriber_alive = &guard;
+        let _keep_client_guard_alive = &client_guard;
+        let subscription = subscription.clone();
+        async move {
+            match item {
+                Ok(msg) if subscription.matches(&msg) => serde_json::to_string(&msg)
+                    .ok()
+                    .map(|s| Ok(Event::default().event("duty_location_update").data(s))),
+                Ok(_) => None,
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    warn!(skipped, "sse client lagged behind broadcast, skipping ahead");
+                    None
+                }
+            }
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TelemetryPayload;
+
+    fn claims(sub: Option<&str>) -> auth::JwtClaims {
+        auth::JwtClaims {
+            sub: sub.map(str::to_string),
+            exp: 0,
+            jti: uuid::Uuid::nil(),
+        }
+    }
+
+    fn msg(unit: Option<&str>, user: &str) -> WsMessage {
+        WsMessage {
+            event: "duty_location_update".to_string(),
+            data: TelemetryPayload {
+                user_id: user.to_string(),
+                lat: 0.0,
+                lon: 0.0,
+                accuracy_m: None,
+                unit_label: unit.map(str::to_string),
+            },
+        }
+    }
+
+    #[test]
+    fn from_query_defaults_to_all() {
+        let query = StreamQuery {
+            unit: None,
+            user: None,
+        };
+        let sub = Subscription::from_query(&query, &claims(Some("alice"))).unwrap();
+        assert!(matches!(sub, Subscription::All));
+    }
+
+    #[test]
+    fn from_query_unit_filter_is_unrestricted() {
+        let query = StreamQuery {
+            unit: Some("alpha3".to_string()),
+            user: None,
+        };
+        let sub = Subscription::from_query(&query, &claims(Some("alice"))).unwrap();
+        assert!(matches!(sub, Subscription::Unit(u) if u == "alpha3"));
+    }
+
+    #[test]
+    fn from_query_user_filter_allows_own_session() {
+        let query = StreamQuery {
+            unit: None,
+            user: Some("alice".to_string()),
+        };
+        let sub = Subscription::from_query(&query, &claims(Some("alice"))).unwrap();
+        assert!(matches!(sub, Subscription::User(u) if u == "alice"));
+    }
+
+    #[test]
+    fn from_query_user_filter_rejects_other_sessions() {
+        let query = StreamQuery {
+            unit: None,
+            user: Some("bob".to_string()),
+        };
+        let err = Subscription::from_query(&query, &claims(Some("alice"))).unwrap_err();
+        assert!(matches!(err, NodeError::Unauthorized));
+    }
+
+    #[test]
+    fn unit_subscription_matches_by_unit_label_only() {
+        let sub = Subscription::Unit("alpha3".to_string());
+        assert!(sub.matches(&msg(Some("alpha3"), "alice")));
+        assert!(!sub.matches(&msg(Some("bravo1"), "alice")));
+        assert!(!sub.matches(&msg(None, "alice")));
+    }
+
+    #[test]
+    fn user_subscription_matches_by_user_id_only() {
+        let sub = Subscription::User("alice".to_string());
+        assert!(sub.matches(&msg(Some("alpha3"), "alice")));
+        assert!(!sub.matches(&msg(Some("alpha3"), "bob")));
+    }
+
+    #[test]
+    fn all_subscription_matches_everything() {
+        assert!(Subscription::All.matches(&msg(None, "alice")));
+    }
+}
@@ -0,0 +1,31 @@
+//! Optional TLS termination via rustls.
+//!
+//! Set `TLS_CERT_PATH`/`TLS_KEY_PATH` to terminate TLS (and negotiate HTTP/2
+//! over ALPN, which benefits the long-lived WS/SSE streaming connections)
+//! directly in the node. Leaving either unset falls back to the current
+//! plaintext behavior so local development is unaffected.
+
+use crate::NodeError;
+use axum_server::tls_rustls::RustlsConfig;
+
+pub struct TlsPaths {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// Reads `TLS_CERT_PATH`/`TLS_KEY_PATH`. Both must be set for TLS to be
+/// enabled; either being absent is treated as "stay plaintext", not an error.
+pub fn from_env() -> Option<TlsPaths> {
+    let cert_path = std::env::var("TLS_CERT_PATH").ok()?;
+    let key_path = std::env::var("TLS_KEY_PATH").ok()?;
+    Some(TlsPaths {
+        cert_path,
+        key_path,
+    })
+}
+
+pub async fn load_rustls_config(paths: &TlsPaths) -> Result<RustlsConfig, NodeError> {
+    RustlsConfig::from_pem_file(&paths.cert_path, &paths.key_path)
+        .await
+        .map_err(|e| NodeError::Internal(format!("failed to load TLS cert/key: {e}")))
+}